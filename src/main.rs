@@ -7,24 +7,69 @@ use reqwest::Url;
 
 use diem_types::{
     chain_id::ChainId,
-    ledger_info::LedgerInfoWithSignatures,
+    ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
     epoch_change::EpochChangeProof,
     proof::{
         AccumulatorConsistencyProof,
     },
     trusted_state::{TrustedState, TrustedStateChange},
+    transaction::TransactionListWithProof,
+    waypoint::Waypoint,
+    account_address::AccountAddress,
+    account_state::AccountState,
+    contract_event::EventWithProof,
+    event::EventKey,
+    proof::{AccountStateProof, TransactionInfoWithProof},
 };
 use diem_json_rpc_client::{
     get_response_from_batch,
     views::{
         AccountStateWithProofView, AccountView, BytesView, CurrencyInfoView,
-        EventView, StateProofView, TransactionView, TransactionDataView
+        EventView, EventWithProofView, StateProofView, TransactionListWithProofView,
     },
     JsonRpcBatch, JsonRpcClient, JsonRpcResponse, ResponseAsView,
 };
 use std::{convert::TryFrom};
+use std::path::{Path, PathBuf};
 use diem_json_rpc_types::views::AmountView;
 use diem_types::account_state_blob::{AccountStateWithProof, AccountStateBlob};
+use move_core_types::identifier::Identifier;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Currencies whose balances we decode from a verified account state. Matches the
+/// currencies exposed on the Diem testnet.
+const KNOWN_CURRENCIES: &[&str] = &["XUS", "XDX"];
+
+/// Maximum number of events fetched per event stream in each `get_events_with_proofs` request.
+const EVENT_FETCH_LIMIT: u64 = 100;
+
+/// On-disk record of everything needed to resume the light client without re-verifying
+/// epoch history the process has already trusted. Serialized with `bcs`, mirroring how the
+/// RPC proof payloads themselves are encoded.
+#[derive(Serialize, Deserialize)]
+struct StartupInfo {
+    trusted_state: TrustedState,
+    latest_epoch_change_li: Option<LedgerInfoWithSignatures>,
+    latest_li: Option<LedgerInfoWithSignatures>,
+    synced_version: u64,
+}
+
+impl StartupInfo {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = std::fs::read(path)?;
+        Ok(Some(bcs::from_bytes(&bytes)?))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let bytes = bcs::to_bytes(self)?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "pDiem")]
@@ -33,41 +78,104 @@ struct Args {
     default_value = "http://127.0.0.1:8080", long,
     help = "Diem rpc endpoint")]
     diem_rpc_endpoint: String,
+
+    #[structopt(
+    long,
+    help = "Trusted waypoint (version:ledger-info-hash) to bootstrap the light client from, \
+            instead of trusting the first epoch-change ledger info returned by the server")]
+    waypoint: Option<String>,
+
+    #[structopt(
+    default_value = ".pdiem", long,
+    help = "Directory to persist trusted state in across restarts")]
+    state_dir: String,
+
+    #[structopt(
+    long,
+    help = "Account address to watch for verified sent/received events")]
+    monitor_account: Option<String>,
 }
 
 pub struct LibraDemo {
     chain_id: ChainId,
     rpc_client: JsonRpcClient,
+    waypoint: Option<Waypoint>,
+    state_path: Option<PathBuf>,
     trusted_state: Option<TrustedState>,
     latest_epoch_change_li: Option<LedgerInfoWithSignatures>,
     latest_li: Option<LedgerInfoWithSignatures>,
+    synced_version: u64,
+    monitor_account: Option<AccountAddress>,
     sent_events_key: Option<BytesView>,
     received_events_key:Option<BytesView>,
+    sent_events_seq_num: u64,
+    received_events_seq_num: u64,
     sent_events: Option<Vec<EventView>>,
     received_events: Option<Vec<EventView>>,
-    transactions: Option<Vec<TransactionView>>,
     //account: Option<AccountData>,
     balances: Option<Vec<AmountView>>,
 }
 impl LibraDemo {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(
+        url: &str,
+        waypoint: Option<Waypoint>,
+        state_path: Option<PathBuf>,
+        monitor_account: Option<AccountAddress>,
+    ) -> Result<Self> {
         let rpc_client = JsonRpcClient::new(Url::parse(url).unwrap()).unwrap();
+        // A corrupt or format-incompatible state file must not be silently treated as "no
+        // persisted state": that would quietly throw away crash-safety progress and
+        // re-bootstrap trust from the server without any warning. Surface the error instead.
+        let startup_info = match state_path.as_deref() {
+            Some(path) => StartupInfo::load(path)?,
+            None => None,
+        };
+
+        let (trusted_state, latest_epoch_change_li, latest_li, synced_version) =
+            match startup_info {
+                Some(info) => {
+                    println!("resuming from persisted state at version {}", info.synced_version);
+                    (Some(info.trusted_state), info.latest_epoch_change_li, info.latest_li, info.synced_version)
+                }
+                None => (None, None, None, 0),
+            };
+
         Ok(LibraDemo {
             chain_id: ChainId::new(2),
             rpc_client,
+            waypoint,
+            state_path,
+            monitor_account,
             sent_events_key: None,
             received_events_key: None,
-            trusted_state: None,
-            latest_epoch_change_li: None,
-            latest_li: None,
+            sent_events_seq_num: 0,
+            received_events_seq_num: 0,
+            trusted_state,
+            latest_epoch_change_li,
+            latest_li,
+            synced_version,
             sent_events: None,
             received_events: None,
-            transactions:None,
             //account: None,
             balances: None,
         })
     }
 
+    /// Write the current trusted state to `self.state_path`, if configured, so a restart can
+    /// resume from it instead of re-verifying already-trusted epochs from scratch.
+    fn persist_state(&self) -> Result<()> {
+        if let Some(path) = &self.state_path {
+            let info = StartupInfo {
+                trusted_state: self.trusted_state.clone().expect("trusted_state must be initialized before persisting"),
+                latest_epoch_change_li: self.latest_epoch_change_li.clone(),
+                latest_li: self.latest_li.clone(),
+                synced_version: self.synced_version,
+            };
+            info.save(path)?;
+        }
+        Ok(())
+    }
+
     pub fn init_state(
         &mut self,
         from_version: u64
@@ -89,21 +197,44 @@ impl LibraDemo {
 
         let ledger_consistency_proof: AccumulatorConsistencyProof =
             bcs::from_bytes(&state_proof.ledger_consistency_proof.into_bytes().unwrap()).unwrap();
-        // Init zero version state
-        let zero_ledger_info_with_sigs = epoch_change_proof.ledger_info_with_sigs[0].clone();
 
-        self.latest_epoch_change_li = Option::from(zero_ledger_info_with_sigs.clone());
-        self.trusted_state = Option::from(TrustedState::try_from(zero_ledger_info_with_sigs.ledger_info()).unwrap());
-        self.latest_li = Option::from(ledger_info_with_signatures.clone());
+        // Only bootstrap the initial trusted state once: a state loaded from a persisted
+        // StartupInfo record is already trusted and must not be re-derived from scratch.
+        if self.trusted_state.is_none() {
+            match self.waypoint {
+                Some(waypoint) => {
+                    // Do not trust the server's epoch-change history at face value: anchor the
+                    // initial trusted state on an out-of-band waypoint instead. The waypoint's
+                    // (version, hash) commitment is checked against the first epoch-change
+                    // ledger info by verify_and_ratchet below before its validator set is adopted.
+                    self.trusted_state = Option::from(TrustedState::from(waypoint));
+                }
+                None => {
+                    // Init zero version state
+                    let zero_ledger_info_with_sigs = epoch_change_proof.ledger_info_with_sigs[0].clone();
+                    self.latest_epoch_change_li = Option::from(zero_ledger_info_with_sigs.clone());
+                    self.trusted_state = Option::from(TrustedState::try_from(zero_ledger_info_with_sigs.ledger_info()).unwrap());
+                }
+            }
+        }
 
-        // Update Latest version state
-        let _ = self.verify_state_proof(ledger_info_with_signatures, epoch_change_proof);
+        // Do not adopt `ledger_info_with_signatures` as `self.latest_li` (and hence as the
+        // anchor every other proof check in this client trusts) until it has actually been
+        // verified against `trusted_state`/the waypoint. On failure, propagate the error and
+        // leave the previously-trusted `latest_li` (and any on-disk copy of it) untouched.
+        self.verify_state_proof(ledger_info_with_signatures.clone(), epoch_change_proof)?;
+        self.latest_li = Option::from(ledger_info_with_signatures);
+        self.persist_state()?;
         println!("{:#?}", self.trusted_state);
         println!("{:#?}", self.latest_li);
         println!("{:#?}", self.latest_epoch_change_li);
         Ok(())
     }
 
+    pub fn synced_version(&self) -> u64 {
+        self.synced_version
+    }
+
     pub fn verify_state_proof(
         &mut self,
         li: LedgerInfoWithSignatures,
@@ -151,63 +282,324 @@ impl LibraDemo {
         Ok(())
     }
 
-    pub fn get_transactions(
+    /// Fetch `[start_version, start_version + limit)` along with an accumulator range proof
+    /// and verify it against the transaction-accumulator root committed in `ledger_info`
+    /// before accepting the transactions. On any proof-verification failure the chunk is
+    /// rejected and the caller's sync cursor must not be advanced.
+    fn get_verified_transactions_against(
         &mut self,
         start_version: u64,
         limit: u64,
-        include_events: bool
-    ) -> Result<()> {
+        include_events: bool,
+        ledger_info: &LedgerInfo,
+    ) -> Result<TransactionListWithProof> {
         let mut batch = JsonRpcBatch::new();
-        batch.add_get_transactions_request(start_version, limit, include_events);
+        // Anchor the server's proof to the exact ledger version we verify against below:
+        // an accumulator range proof is only consistent with the root of the accumulator it
+        // was generated for, so if the server picked its own (possibly newer) latest version
+        // instead, the proof would fail to connect to `ledger_info.transaction_accumulator_hash()`.
+        batch.add_get_transactions_with_proofs_request(
+            start_version,
+            limit,
+            include_events,
+            Some(ledger_info.version()),
+        );
         let responses = self.rpc_client.execute(batch).unwrap();
-        //println!("response:{:?}", responses);
         let resp = get_response_from_batch(0, &responses).unwrap().as_ref().unwrap();
-        self.transactions = Option::from(TransactionView::vec_from_response(resp.clone()).unwrap());
-        let transactions= self.transactions.as_ref().unwrap().clone();
-        for transaction in transactions {
-            println!("transaction version:{:?}, transaction hash:{:?}", transaction.version, transaction.hash);
-            match transaction.transaction {
-                TransactionDataView::UserTransaction { .. } => {
-                    //println!("sender:\n{:?}", sender);
-                    println!("transaction:\n{:?}", transaction);
-                },
-                TransactionDataView::BlockMetadata { timestamp_usecs} => {
-                    //println!("transaction:\n{:?}", transaction);
-                    println!("BlockMetadata");
-                }
-                TransactionDataView::WriteSet { } => {
-                    println!("WriteSet");
-                }
-                TransactionDataView::UnknownTransaction { } => {
-                    println!("UnknownTransaction");
-                }
+        let txn_list_view = TransactionListWithProofView::from_response(resp.clone()).unwrap();
+
+        let txn_list_with_proof: TransactionListWithProof =
+            bcs::from_bytes(&txn_list_view.txn_list_with_proof.into_bytes().unwrap())?;
+
+        // Checks that `first_transaction_version`, the raw transactions and the accompanying
+        // transaction-info accumulator range proof are internally consistent, and that the
+        // range proof connects to the accumulator root hash committed in `ledger_info`.
+        txn_list_with_proof
+            .verify(ledger_info, Some(start_version))
+            .map_err(|e| anyhow::anyhow!("failed to verify transaction list proof: {}", e))?;
+
+        println!(
+            "verified {} transaction(s) starting at version {}",
+            txn_list_with_proof.transactions.len(),
+            start_version
+        );
+        Ok(txn_list_with_proof)
+    }
+
+    /// Fetch and verify `[start_version, start_version + limit)` against `self.latest_li`,
+    /// the most recently verified ledger info. This is a read-only query: unlike `sync`, it
+    /// does not advance or persist `self.synced_version`, so it is safe to call for an
+    /// arbitrary, non-sequential version range without perturbing catch-up progress.
+    pub fn get_verified_transactions(
+        &mut self,
+        start_version: u64,
+        limit: u64,
+        include_events: bool,
+    ) -> Result<TransactionListWithProof> {
+        let latest_li = self
+            .latest_li
+            .as_ref()
+            .expect("trusted_state must be initialized before fetching transactions")
+            .ledger_info()
+            .clone();
+        self.get_verified_transactions_against(start_version, limit, include_events, &latest_li)
+    }
+
+    /// Drive state-sync forward from `self.synced_version()` to the version of the most
+    /// recently verified ledger info, fetching transactions in chunks of at most `limit`.
+    /// Every chunk, regardless of how many epoch changes it spans, is verified against the
+    /// same `self.latest_li`: the accumulator range proof authenticates transactions by their
+    /// position in the single global transaction accumulator, not by validator set, so there
+    /// is no epoch-boundary case to split here.
+    pub fn sync(&mut self, limit: u64, include_events: bool) -> Result<()> {
+        let target_version = self
+            .latest_li
+            .as_ref()
+            .expect("trusted_state must be initialized before syncing")
+            .ledger_info()
+            .version();
+
+        while self.synced_version < target_version {
+            let chunk_start = self.synced_version + 1;
+            let chunk_end = std::cmp::min(chunk_start + limit - 1, target_version);
+            let chunk_limit = chunk_end - chunk_start + 1;
+            let latest_li = self.latest_li.as_ref().unwrap().ledger_info().clone();
+            let txn_list_with_proof = self.get_verified_transactions_against(
+                chunk_start,
+                chunk_limit,
+                include_events,
+                &latest_li,
+            )?;
+
+            let num_txns = txn_list_with_proof.transactions.len() as u64;
+            if num_txns == 0 {
+                // Server has nothing more to offer for this range; stop rather than spin.
+                break;
             }
+            self.synced_version = chunk_start + num_txns - 1;
+            self.persist_state()?;
+        }
+        Ok(())
+    }
+
+    /// Fetch `address`'s account state along with a Sparse Merkle proof and verify it against
+    /// the state-root hash committed in `self.latest_li`'s `LedgerInfo` before trusting it.
+    /// On success, decodes the account blob and populates `self.balances`.
+    pub fn get_account_state(&mut self, address: AccountAddress) -> Result<AccountStateWithProof> {
+        let ledger_version = self
+            .latest_li
+            .as_ref()
+            .expect("trusted_state must be initialized before fetching account state")
+            .ledger_info()
+            .version();
+
+        let mut batch = JsonRpcBatch::new();
+        // Pin the anchoring ledger version to the one we verify against below, otherwise the
+        // server may build `ledger_info_to_transaction_info_proof` against its own (possibly
+        // newer) latest ledger info, which would fail to connect to `self.latest_li`'s root.
+        batch.add_get_account_state_with_proof_request(address, None, Some(ledger_version));
+        let responses = self.rpc_client.execute(batch).unwrap();
+        let resp = get_response_from_batch(0, &responses).unwrap().as_ref().unwrap();
+        let view = AccountStateWithProofView::from_response(resp.clone()).unwrap();
+
+        let blob: Option<AccountStateBlob> = view
+            .blob
+            .map(|b| bcs::from_bytes(&b.into_bytes().unwrap()))
+            .transpose()?;
+        let ledger_info_to_transaction_info_proof =
+            bcs::from_bytes(&view.proof.ledger_info_to_transaction_info_proof.into_bytes().unwrap())?;
+        let transaction_info =
+            bcs::from_bytes(&view.proof.transaction_info.into_bytes().unwrap())?;
+        let transaction_info_to_account_proof =
+            bcs::from_bytes(&view.proof.transaction_info_to_account_proof.into_bytes().unwrap())?;
+
+        let account_state_with_proof = AccountStateWithProof::new(
+            view.version,
+            blob,
+            AccountStateProof::new(
+                TransactionInfoWithProof::new(ledger_info_to_transaction_info_proof, transaction_info),
+                transaction_info_to_account_proof,
+            ),
+        );
+
+        let latest_li = self
+            .latest_li
+            .as_ref()
+            .expect("trusted_state must be initialized before fetching account state")
+            .ledger_info();
+
+        // Checks the sparse merkle proof of (the presence or absence of) the account blob
+        // against the state-root hash committed in `latest_li`.
+        account_state_with_proof
+            .verify(latest_li, view.version, address)
+            .map_err(|e| anyhow::anyhow!("failed to verify account state proof: {}", e))?;
+
+        self.balances = account_state_with_proof
+            .blob
+            .as_ref()
+            .map(|blob| AccountState::try_from(blob))
+            .transpose()?
+            .map(|account_state| {
+                let currencies: Vec<Identifier> = KNOWN_CURRENCIES
+                    .iter()
+                    .map(|code| Identifier::new(*code).unwrap())
+                    .collect();
+                account_state
+                    .get_balance_resources(&currencies)
+                    .map(|balances| {
+                        balances
+                            .into_iter()
+                            .map(|(code, balance)| AmountView {
+                                amount: balance.coin(),
+                                currency: code.to_string(),
+                            })
+                            .collect::<Vec<_>>()
+                    })
+            })
+            .transpose()?;
+
+        Ok(account_state_with_proof)
+    }
+
+    /// Poll the monitored account's sent/received event streams for new events since the last
+    /// seen sequence number, verify each one against `self.latest_li`, and emit it on `tx`.
+    /// A no-op when no `--monitor-account` was configured.
+    pub fn poll_monitored_events(&mut self, tx: &UnboundedSender<EventView>) -> Result<()> {
+        let address = match self.monitor_account {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+
+        if self.sent_events_key.is_none() || self.received_events_key.is_none() {
+            let account_state_with_proof = self.get_account_state(address)?;
+            let account_state = account_state_with_proof
+                .blob
+                .as_ref()
+                .map(AccountState::try_from)
+                .transpose()?
+                .ok_or_else(|| anyhow::anyhow!("no account state found for {}", address))?;
+            let account_resource = account_state
+                .get_account_resource()?
+                .ok_or_else(|| anyhow::anyhow!("no AccountResource found for {}", address))?;
+            self.sent_events_key = Some(BytesView::from(account_resource.sent_events().key().to_vec()));
+            self.received_events_key =
+                Some(BytesView::from(account_resource.received_events().key().to_vec()));
         }
+
+        let sent_events_key = self.sent_events_key.clone().unwrap();
+        let (events, next_seq_num) =
+            self.fetch_and_verify_events(&sent_events_key, self.sent_events_seq_num, tx)?;
+        self.sent_events_seq_num = next_seq_num;
+        self.sent_events = Option::from(events);
+
+        let received_events_key = self.received_events_key.clone().unwrap();
+        let (events, next_seq_num) =
+            self.fetch_and_verify_events(&received_events_key, self.received_events_seq_num, tx)?;
+        self.received_events_seq_num = next_seq_num;
+        self.received_events = Option::from(events);
+
         Ok(())
     }
+
+    /// Fetch up to `EVENT_FETCH_LIMIT` events starting at `start_seq_num` for `event_key`,
+    /// verify each one's `EventWithProof` against the event accumulator committed in
+    /// `self.latest_li`, send verified events on `tx`, and return them along with the next
+    /// sequence number to resume from.
+    fn fetch_and_verify_events(
+        &mut self,
+        event_key: &BytesView,
+        start_seq_num: u64,
+        tx: &UnboundedSender<EventView>,
+    ) -> Result<(Vec<EventView>, u64)> {
+        let ledger_version = self
+            .latest_li
+            .as_ref()
+            .expect("trusted_state must be initialized before polling events")
+            .ledger_info()
+            .version();
+
+        let mut batch = JsonRpcBatch::new();
+        // Pin the anchoring ledger version to the one each event is verified against below,
+        // for the same reason as the transaction and account-state proof requests: the
+        // server would otherwise anchor against its own latest ledger info rather than the
+        // one we actually trust.
+        batch.add_get_events_with_proofs_request(
+            event_key.clone(),
+            start_seq_num,
+            EVENT_FETCH_LIMIT,
+            Some(ledger_version),
+        );
+        let responses = self.rpc_client.execute(batch).unwrap();
+        let resp = get_response_from_batch(0, &responses).unwrap().as_ref().unwrap();
+        let views = EventWithProofView::vec_from_response(resp.clone()).unwrap();
+
+        let latest_li = self
+            .latest_li
+            .as_ref()
+            .expect("trusted_state must be initialized before polling events")
+            .ledger_info();
+        let key = EventKey::try_from(&event_key.clone().into_bytes().unwrap()[..])?;
+
+        let mut verified_events = Vec::with_capacity(views.len());
+        let mut seq_num = start_seq_num;
+        for view in views {
+            let event_with_proof: EventWithProof =
+                bcs::from_bytes(&view.event_with_proof.into_bytes().unwrap())?;
+
+            // Checks that the event belongs to `key`'s stream at sequence number `seq_num`,
+            // and that its proof connects it, through the event accumulator committed in its
+            // transaction info, to `latest_li`.
+            event_with_proof
+                .verify(latest_li, &key, seq_num)
+                .map_err(|e| anyhow::anyhow!("failed to verify event proof: {}", e))?;
+
+            let event_view = EventView::try_from((event_with_proof.transaction_version, event_with_proof.event))?;
+            let _ = tx.send(event_view.clone());
+            verified_events.push(event_view);
+            seq_num += 1;
+        }
+
+        Ok((verified_events, seq_num))
+    }
 }
 
 async fn bridge(args: Args) {
     //official endpoint: https://testnet.diem.com/v1
-    let mut demo = LibraDemo::new(&args.diem_rpc_endpoint).unwrap();
+    let waypoint = args
+        .waypoint
+        .as_ref()
+        .map(|w| w.parse::<Waypoint>().expect("invalid --waypoint"));
+    let monitor_account = args
+        .monitor_account
+        .as_ref()
+        .map(|a| AccountAddress::from_hex_literal(a).expect("invalid --monitor-account"));
+    std::fs::create_dir_all(&args.state_dir).unwrap();
+    let state_path = Some(PathBuf::from(&args.state_dir).join("startup_info.bcs"));
+    let mut demo =
+        LibraDemo::new(&args.diem_rpc_endpoint, waypoint, state_path, monitor_account).unwrap();
+
+    let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel::<EventView>();
+    tokio::spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            println!("verified event:\n{:?}", event);
+        }
+    });
 
-    let known_version = 0;
-    let mut start: u64 = 0;
-    let mut limit: u64 = 100;
-    let new_limit: u64 =1;
+    let limit: u64 = 100;
     loop {
-        let _ = demo.init_state(known_version);
-        let new_version = demo.trusted_state.as_ref().unwrap().latest_version();
-        let end = new_version / limit;
-        for index in start..end {
-            let _ = demo.get_transactions(index * limit + 1, limit, true);
-            if index > 0 && index % 100 == 0 {
-                delay_for(Duration::from_millis(300)).await;
-            }
+        let known_version = demo.synced_version();
+        if let Err(e) = demo.init_state(known_version) {
+            // The server's ledger info failed verification against trusted_state/the
+            // waypoint: do not proceed to sync/poll against it, retry on the next tick.
+            println!("state verification failed, retrying: {:?}", e);
+            delay_for(Duration::from_millis(5000)).await;
+            continue;
         }
+        let _ = demo.poll_monitored_events(&event_tx);
 
-        start = end * limit / new_limit;
-        limit = new_limit;
+        if let Err(e) = demo.sync(limit, true) {
+            println!("sync error: {:?}", e);
+        }
 
         println!("waiting for new versions...");
         delay_for(Duration::from_millis(5000)).await;